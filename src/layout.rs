@@ -1,11 +1,19 @@
 use keyberon::action::{k, m, Action::*, HoldTapAction, HoldTapConfig};
 use keyberon::key_code::KeyCode::*;
 
-type Action = keyberon::action::Action<()>;
+use crate::CustomAction;
+
+type Action = keyberon::action::Action<CustomAction>;
 
 static DLAYER: Action = Action::DefaultLayer(0);
 static QWERTZLAYER: Action = Action::DefaultLayer(4);
 
+/// Jump to the DFU bootloader for flashing.
+const BOOTLOADER: Action = Action::Custom(CustomAction::Bootloader);
+
+/// Cycle the underglow mode.
+const LED_TOGGLE: Action = Action::Custom(CustomAction::LedToggle);
+
 const TIMEOUT: u16 = 200;
 
 const SHIFT_SP: Action = HoldTap(&HoldTapAction {
@@ -52,7 +60,7 @@ macro_rules! a {
 }
 
 #[rustfmt::skip]
-pub static LAYERS: keyberon::layout::Layers<7, 10, 5, ()> = keyberon::layout::layout! {
+pub static LAYERS: keyberon::layout::Layers<7, 10, 5, CustomAction> = keyberon::layout::layout! {
     {
         // left half
         [ n     n     1     2     3     4     5],
@@ -98,14 +106,14 @@ pub static LAYERS: keyberon::layout::Layers<7, 10, 5, ()> = keyberon::layout::la
     }{
         // left half
         [ t            t     t     t     t      t     t],
-        [{Custom(())}  n     n     n     n    VolUp   n],
+        [{BOOTLOADER}  n     n     n     n    VolUp   n],
         [t             n     n     n     n   {PPN}    n],
         [t             n     n     n     n   VolDown  n],
         [t             t     t     t     t     t      t],
 
         // right half
         [ t   t   t   t   t     t       t],
-        [F12  F7  F8  F9  n     n  {Custom(())}],
+        [F12  F7  F8  F9  n     n  {LED_TOGGLE}],
         [F11  F4  F5  F6  n     n      t],
         [n    F10 F1  F2  F3    n      t],
         [t    t {QWERTZLAYER} t t t    t],
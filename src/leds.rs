@@ -0,0 +1,125 @@
+//! WS2812 underglow backend driven over SPI.
+//!
+//! The strip is wired to the SPI MOSI line and fed through [`ws2812_spi`].
+//! The backend doubles as the [`keyberon::keyboard::Leds`] sink for the USB
+//! class, so it lives inside the [`UsbClass`](crate::UsbClass) and is stepped
+//! once per keyboard [`tick`](crate::app::tick) to advance animations.
+
+use smart_leds::{brightness, colors, hsv::hsv2rgb, hsv::Hsv, SmartLedsWrite, RGB8};
+use stm32f1xx_hal::pac::SPI2;
+use stm32f1xx_hal::spi::{NoMiso, NoSck, Spi, Spi2NoRemap};
+use stm32f1xx_hal::gpio::gpiob::PB15;
+use stm32f1xx_hal::gpio::{Alternate, PushPull};
+use ws2812_spi::Ws2812;
+
+/// Number of WS2812 LEDs on the underglow strip of a single half.
+pub const NUM_LEDS: usize = 10;
+
+/// The concrete SPI backend for the underglow strip: only MOSI (`PB15`) is
+/// connected, clocked from `SPI2`.
+type UnderglowSpi = Spi<
+    SPI2,
+    Spi2NoRemap,
+    (NoSck, NoMiso, PB15<Alternate<PushPull>>),
+    u8,
+>;
+
+/// Underglow animation modes cycled through by the custom layout actions.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Strip off.
+    Off,
+    /// A single static color across the whole strip.
+    Solid,
+    /// Color chosen from the currently active layer.
+    PerLayer,
+    /// The per-layer color with a breathing brightness envelope.
+    Breathing,
+}
+
+impl Mode {
+    /// The next mode in the cycle, wrapping back to [`Mode::Off`].
+    fn next(self) -> Self {
+        match self {
+            Mode::Off => Mode::Solid,
+            Mode::Solid => Mode::PerLayer,
+            Mode::PerLayer => Mode::Breathing,
+            Mode::Breathing => Mode::Off,
+        }
+    }
+}
+
+/// WS2812 underglow backend: owns the SPI writer and the animation state.
+pub struct Leds {
+    ws: Ws2812<UnderglowSpi>,
+    mode: Mode,
+    /// Active layer, refreshed from the layout before every frame.
+    layer: usize,
+    /// Phase counter advanced each tick (wrapping at 256), used by the animations.
+    phase: u8,
+}
+
+impl Leds {
+    /// Wrap an `SPI2` bus driving the underglow strip. Starts in
+    /// [`Mode::PerLayer`] so the base layer lights up at power-on.
+    pub fn new(spi: UnderglowSpi) -> Self {
+        Self {
+            ws: Ws2812::new(spi),
+            mode: Mode::PerLayer,
+            layer: 0,
+            phase: 0,
+        }
+    }
+
+    /// Advance to the next underglow mode.
+    pub fn next_mode(&mut self) {
+        self.mode = self.mode.next();
+    }
+
+    /// Record the layer the layout is currently on so [`Self::tick`] can color
+    /// the strip to match.
+    pub fn set_layer(&mut self, layer: usize) {
+        self.layer = layer;
+    }
+
+    /// Base color for the current layer. The hue tracks the layout's layers so
+    /// the backlight reflects whether we are on QWERTZ, symbol or nav.
+    fn layer_color(&self) -> RGB8 {
+        hsv2rgb(Hsv {
+            hue: (self.layer as u8).wrapping_mul(48),
+            sat: 255,
+            val: 255,
+        })
+    }
+
+    /// Push one animation frame to the strip. Called once per keyboard tick.
+    pub fn tick(&mut self) {
+        self.phase = self.phase.wrapping_add(1);
+
+        let color = match self.mode {
+            Mode::Off => RGB8::default(),
+            Mode::Solid => colors::WHITE,
+            Mode::PerLayer => self.layer_color(),
+            Mode::Breathing => self.layer_color(),
+        };
+
+        // A triangle wave over the phase counter gives the breathing envelope;
+        // every other mode runs at full brightness.
+        let level = match self.mode {
+            Mode::Breathing => {
+                let p = self.phase;
+                if p < 128 {
+                    p.wrapping_mul(2)
+                } else {
+                    (255 - p).wrapping_mul(2)
+                }
+            }
+            _ => 255,
+        };
+
+        let frame = core::iter::repeat(color).take(NUM_LEDS);
+        self.ws.write(brightness(frame, level)).ok();
+    }
+}
+
+impl keyberon::keyboard::Leds for Leds {}
@@ -6,19 +6,26 @@ use cortex_m::asm::delay;
 use defmt_rtt as _; // global logger
 use hal::gpio::{EPin, Input, Output, PullUp, PushPull};
 use hal::prelude::*;
+use hal::serial::{Config, Rx, Serial, Tx};
+use hal::spi::{NoMiso, NoSck, Spi};
 use hal::usb::{Peripheral, UsbBus, UsbBusType};
+use heapless::spsc::{Consumer, Producer, Queue};
 use keyberon::debounce::Debouncer;
 use keyberon::key_code::KbHidReport;
-use keyberon::layout::{CustomEvent, Layout};
+use keyberon::layout::{CustomEvent, Event, Layout};
 use keyberon::matrix::Matrix;
 use stm32f1xx_hal as hal;
 use usb_device::bus::UsbBusAllocator;
 use usb_device::class::UsbClass as _;
+use usb_device::device::UsbDeviceState;
 use usb_device::prelude::*;
 
 use panic_probe as _;
 
 pub mod layout;
+pub mod leds;
+
+use leds::Leds;
 
 /// USB VIP for a generic keyboard from
 /// https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt
@@ -50,9 +57,80 @@ pub fn exit() -> ! {
     }
 }
 
-type UsbClass = keyberon::Class<'static, UsbBusType, ()>;
+type UsbClass = keyberon::Class<'static, UsbBusType, Leds>;
 type UsbDevice = usb_device::device::UsbDevice<'static, UsbBusType>;
 
+/// Custom layout actions dispatched in [`app::tick`]. Giving each behavior its
+/// own variant keeps `Custom` entries in the layers distinguishable instead of
+/// overloading a single nullary event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomAction {
+    /// Jump to the built-in DFU bootloader.
+    Bootloader,
+    /// Cycle the WS2812 underglow mode.
+    LedToggle,
+}
+
+/// Drive a remote-wakeup resume (K state) onto the bus so a keystroke can wake
+/// a suspended host. Callers must first confirm the host armed remote wakeup
+/// (see [`UsbDevice::remote_wakeup_enabled`]). The peripheral is otherwise owned
+/// by the USB interrupts, so the sequence runs inside a critical section.
+///
+/// On SUSPEND the USB core puts the macrocell into its low-power state
+/// (`FSUSP` + `LP_MODE`); both must be cleared before the RESUME bit has any
+/// effect, after which we hold the K state for the ~2 ms the spec requires.
+fn remote_wakeup() {
+    cortex_m::interrupt::free(|_| {
+        // SAFETY: the USB interrupts that own the peripheral are masked for the
+        // duration of this critical section, so the brief register access here
+        // cannot race with the USB driver.
+        let usb = unsafe { &*hal::pac::USB::ptr() };
+        // Leave the suspend low-power state before driving resume.
+        usb.cntr
+            .modify(|_, w| w.fsusp().clear_bit().lpmode().clear_bit());
+        usb.cntr.modify(|_, w| w.resume().set_bit());
+        delay(48_000_000 / 500);
+        usb.cntr.modify(|_, w| w.resume().clear_bit());
+    });
+}
+
+/// Control byte sent over the TRRS link to cycle the remote half's underglow
+/// mode in step with the host. It decodes to an out-of-range index (`127`), so
+/// [`deserialize_event`] already rejects it as a key event; [`app::rx`] handles
+/// it explicitly before attempting event decoding.
+const LED_CYCLE_CMD: u8 = 0x7f;
+
+/// Serialize a debounced local event into a single framed byte for the TRRS
+/// link: the high bit carries press (`1`) / release (`0`), the low 7 bits carry
+/// `row * 7 + col`. A `7x5` half never exceeds `4 * 7 + 6 = 34`, so the index
+/// always fits below the press bit.
+fn serialize_event(event: Event) -> u8 {
+    let (i, j) = event.coord();
+    let index = (i as u8) * 7 + j as u8;
+    match event {
+        Event::Press(..) => 0x80 | index,
+        Event::Release(..) => index,
+    }
+}
+
+/// Decode a byte received over the TRRS link back into an `Event` in the
+/// remote half's local coordinates. Returns `None` for a byte whose index is
+/// out of range, which lets the RX interrupt drop a partial/garbled frame and
+/// resynchronize on the next start bit.
+fn deserialize_event(byte: u8) -> Option<Event> {
+    let index = byte & 0x7f;
+    let i = (index / 7) as u8;
+    let j = (index % 7) as u8;
+    if i >= 5 || j >= 7 {
+        return None;
+    }
+    Some(if byte & 0x80 != 0 {
+        Event::Press(i, j)
+    } else {
+        Event::Release(i, j)
+    })
+}
+
 #[rtic::app(device = stm32f1xx_hal::pac, dispatchers=[TIM1_CC])]
 mod app {
     use super::*;
@@ -62,18 +140,49 @@ mod app {
         usb_dev: UsbDevice,
         usb_class: UsbClass,
         #[lock_free]
-        layout: Layout<7, 10, 5, ()>,
+        layout: Layout<7, 10, 5, CustomAction>,
+        /// `true` on the half whose USB is enumerated: it owns the combined
+        /// layout and emits USB reports. Read by both [`tick`] and [`rx`] to
+        /// key the coordinate offset on which side is the host.
+        #[lock_free]
+        is_host: bool,
     }
 
     // local resources (between tasks)
     #[local]
     struct Local {
-        matrix: Matrix<EPin<Input<PullUp>>, EPin<Output<PushPull>>, 7, 10>,
-        debouncer: Debouncer<[[bool; 7]; 10]>,
+        matrix: Matrix<EPin<Input<PullUp>>, EPin<Output<PushPull>>, 7, 5>,
+        debouncer: Debouncer<[[bool; 7]; 5]>,
         timer: hal::timer::counter::CounterHz<hal::pac::TIM2>,
         delay: cortex_m::delay::Delay,
+        /// TRRS UART halves. Both ends live in the [`rx`] task (the single
+        /// `USART1` vector serves RX and TX): [`rx`] reads inbound frames and
+        /// drains [`tx_consumer`](Local::tx_consumer) onto the wire.
+        serial_tx: Tx<hal::pac::USART1>,
+        serial_rx: Rx<hal::pac::USART1>,
+        /// Producer end of the outbound TRRS byte queue, filled in [`tick`].
+        /// Buffering here (rather than a blocking write in the TIM2 ISR) keeps
+        /// chords/rollover from being dropped when more events occur in one scan
+        /// than the USART can shift out immediately.
+        tx_producer: Producer<'static, u8, TX_QUEUE_LEN>,
+        /// Consumer end of the outbound TRRS byte queue, drained in [`rx`] as
+        /// the transmitter empties.
+        tx_consumer: Consumer<'static, u8, TX_QUEUE_LEN>,
+        /// `true` while scanning at the full 1 kHz rate. Dropped to a slow rate
+        /// while the bus is suspended and restored once it is `Configured`.
+        scanning_fast: bool,
     }
 
+    /// Matrix scan rate while the USB bus is suspended. Low enough to idle the
+    /// MCU between scans, but fast enough to notice the keystroke that should
+    /// wake the host.
+    const SUSPEND_SCAN_HZ: u32 = 50;
+
+    /// Capacity of the outbound TRRS byte queue (one usable slot less). Deep
+    /// enough to hold every event a single scan can produce plus the occasional
+    /// control byte while the USART shifts the backlog out.
+    const TX_QUEUE_LEN: usize = 64;
+
     #[init]
     fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
         // defmt::info!("init");
@@ -122,17 +231,58 @@ mod app {
             USB_BUS = Some(UsbBus::new(usb));
         }
 
+        // WS2812 underglow on SPI2: only MOSI (PB15) is wired to the strip.
+        let mosi = gpiob.pb15.into_alternate_push_pull(&mut gpiob.crh);
+        let spi = Spi::new(
+            cx.device.SPI2,
+            (NoSck, NoMiso, mosi),
+            ws2812_spi::MODE,
+            3.MHz(),
+            &clocks,
+        );
+
         let usb_bus = unsafe { USB_BUS.as_ref().unwrap() };
-        let usb_class = keyberon::new_class(&usb_bus, ());
+        let usb_class = keyberon::new_class(&usb_bus, Leds::new(spi));
         let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(VID, PID))
             .manufacturer("Dario Götz")
             .product("Dario Götz's 42-key split keyboard")
             .serial_number(env!("CARGO_PKG_VERSION"))
+            // Advertise remote-wakeup support so the host can arm it and a
+            // keystroke can resume the bus from suspend.
+            .supports_remote_wakeup(true)
             .build();
 
         // disable jtag functionality on pins PB3 and PB4
         let (_pa15, pb3, pb4) = afio.mapr.disable_jtag(gpioa.pa15, gpiob.pb3, gpiob.pb4);
 
+        // Side detection: the halves flash identical firmware and tell each
+        // other apart via a dedicated jumper on PB8. It is pulled up, so the
+        // half that grounds it through the TRRS wiring enumerates over USB and
+        // becomes the host; the floating half forwards its events instead.
+        let side_pin = gpiob.pb8.into_pull_up_input(&mut gpiob.crh);
+        let is_host = side_pin.is_low();
+
+        // TRRS UART link (USART1 on PB6/PB7). Identical on both halves: the
+        // non-host transmits serialized events, the host listens for them and
+        // sends back the underglow cycle command.
+        let tx_pin = gpiob.pb6.into_alternate_push_pull(&mut gpiob.crl);
+        let rx_pin = gpiob.pb7;
+        let mut serial = Serial::new(
+            cx.device.USART1,
+            (tx_pin, rx_pin),
+            &mut afio.mapr,
+            Config::default().baudrate(38_400.bps()),
+            &clocks,
+        );
+        serial.rx.listen();
+        let (serial_tx, serial_rx) = serial.split();
+
+        // Outbound TRRS byte queue: `tick` enqueues serialized events/commands
+        // and the `USART1` TXE interrupt drains them so a burst never blocks the
+        // scan ISR or overruns the two-byte hardware FIFO.
+        static mut TX_QUEUE: Queue<u8, TX_QUEUE_LEN> = Queue::new();
+        let (tx_producer, tx_consumer) = unsafe { TX_QUEUE.split() };
+
         let cols = [
             gpioa.pa0.into_pull_up_input(&mut gpioa.crl).erase(),
             gpioa.pa1.into_pull_up_input(&mut gpioa.crl).erase(),
@@ -143,17 +293,13 @@ mod app {
             gpioa.pa6.into_pull_up_input(&mut gpioa.crl).erase(),
         ];
 
+        // Each half only drives its own five local rows.
         let rows = [
             gpioa.pa8.into_push_pull_output(&mut gpioa.crh).erase(),
             gpioa.pa9.into_push_pull_output(&mut gpioa.crh).erase(),
             gpioa.pa10.into_push_pull_output(&mut gpioa.crh).erase(),
             pb3.into_push_pull_output(&mut gpiob.crl).erase(),
             pb4.into_push_pull_output(&mut gpiob.crl).erase(),
-            gpiob.pb5.into_push_pull_output(&mut gpiob.crl).erase(),
-            gpiob.pb6.into_push_pull_output(&mut gpiob.crl).erase(),
-            gpiob.pb7.into_push_pull_output(&mut gpiob.crl).erase(),
-            gpiob.pb8.into_push_pull_output(&mut gpiob.crh).erase(),
-            gpiob.pb9.into_push_pull_output(&mut gpiob.crh).erase(),
         ];
 
         let matrix = cortex_m::interrupt::free(move |_cs| Matrix::new(cols, rows));
@@ -166,29 +312,38 @@ mod app {
                 usb_dev,
                 usb_class,
                 layout: Layout::new(&layout::LAYERS),
+                is_host,
             },
             Local {
                 // Initialization of local resources go here
                 matrix: matrix.unwrap(),
                 timer,
-                debouncer: Debouncer::new([[false; 7]; 10], [[false; 7]; 10], 5),
+                debouncer: Debouncer::new([[false; 7]; 5], [[false; 7]; 5], 5),
                 delay,
+                serial_tx,
+                serial_rx,
+                tx_producer,
+                tx_consumer,
+                scanning_fast: true,
             },
             init::Monotonics(),
         )
     }
 
-    // Optional idle, can be removed if not needed.
+    // Sleep between interrupts so a suspended bus draws as little power as
+    // possible; TIM2 and the USB/USART interrupts wake us back up.
     #[idle]
     fn idle(_: idle::Context) -> ! {
         loop {
-            continue;
+            cortex_m::asm::wfi();
         }
     }
 
-    /// Check all switches for their state, register corresponding events, and
-    /// spawn generation of a USB keyboard report (including layout event processing)
-    #[task(binds=TIM2, priority=1, local=[debouncer, matrix, timer, delay], shared=[usb_dev, usb_class, layout])]
+    /// Check all local switches for their state, register corresponding events,
+    /// and — on the host — spawn generation of a USB keyboard report (including
+    /// layout event processing). The non-host half only forwards its debounced
+    /// events to the host over the TRRS link.
+    #[task(binds=TIM2, priority=1, local=[debouncer, matrix, timer, delay, tx_producer, scanning_fast], shared=[usb_dev, usb_class, layout, is_host])]
     fn tick(mut cx: tick::Context) {
         // defmt::info!("Processing keyboard events");
         cx.local.timer.wait().ok();
@@ -196,28 +351,116 @@ mod app {
         // cx.local.timer.clear_interrupt(hal::timer::Event::Update);
 
         let delay = cx.local.delay;
+        let is_host = *cx.shared.is_host;
+
+        let state = cx.shared.usb_dev.lock(|d| d.state());
+        // Only the host's bus can suspend; the non-host is never enumerated (its
+        // D+/D- go to the host, not a host controller), so its state never
+        // leaves `Default` and must not throttle its own scan.
+        let suspended = is_host && state == UsbDeviceState::Suspended;
+
+        // Match the scan rate to the bus state: the non-host always scans at the
+        // full 1 kHz, the host does so once the bus is `Configured` and drops to
+        // a slow rate while suspended so it can sit in `wfi` between scans.
+        let want_fast = !is_host || state == UsbDeviceState::Configured;
+        if want_fast != *cx.local.scanning_fast {
+            let rate = if want_fast {
+                1.kHz()
+            } else {
+                SUSPEND_SCAN_HZ.Hz()
+            };
+            cx.local.timer.start(rate).ok();
+            cx.local.timer.listen(hal::timer::Event::Update);
+            *cx.local.scanning_fast = want_fast;
+        }
 
-        // scan keyboard
-        for event in cx.local.debouncer.events(
+        // scan the local half; while suspended we skip the per-column settle
+        // delay since we only need to notice that a key went down.
+        let scan = if suspended {
+            cx.local.matrix.get().unwrap()
+        } else {
             cx.local
                 .matrix
                 .get_with_delay(|| delay.delay_us(10))
-                .unwrap(),
-        ) {
-            cx.shared.layout.event(event);
-            // match event {
-            //     Event::Press(i, j) => defmt::info!("Pressed {} {}", i, j),
-            //     Event::Release(i, j) => defmt::info!("Released {} {}", i, j),
-            // }
+                .unwrap()
+        };
+
+        // The host keeps its own matrix in rows 0..=4 and receives the partner
+        // into rows 5..=9; keying the offset on `is_host` keeps the mapping
+        // symmetric no matter which half enumerated.
+        let local_base = if is_host { 0 } else { 5 };
+        let mut pressed = false;
+        for event in cx.local.debouncer.events(scan) {
+            if event.is_press() {
+                pressed = true;
+            }
+            if is_host {
+                cx.shared
+                    .layout
+                    .event(event.transform(|i, j| (i + local_base, j)));
+            } else {
+                // Forward to the host. Queue the framed byte and kick the
+                // USART1 ISR to drain it; a scan that changes several keys at
+                // once (chord/rollover) would overrun the two-byte hardware FIFO
+                // if written inline, so the buffer carries the burst instead of
+                // dropping events or busy-waiting in the TIM2 ISR.
+                if cx.local.tx_producer.enqueue(serialize_event(event)).is_ok() {
+                    rtic::pend(hal::pac::Interrupt::USART1);
+                }
+            }
+        }
+
+        // Step the underglow on both halves so each strip animates and tracks
+        // the active layer. The non-host forwards its events rather than owning
+        // the layout, so its `current_layer` stays on the base layer, but its
+        // strip still needs a frame every tick. Skip it while suspended to stay
+        // on the low-power path.
+        if !suspended {
+            let layer = cx.shared.layout.current_layer();
+            cx.shared.usb_class.lock(|k| {
+                let leds = k.device_mut().leds_mut();
+                leds.set_layer(layer);
+                leds.tick();
+            });
+        }
+
+        // only the host owns the layout and drives USB
+        if !is_host {
+            return;
+        }
+
+        // While suspended, a keystroke should wake the host rather than produce
+        // a report (the bus is asleep). Request remote wakeup and stop here so
+        // we stay on the low-power path until the host drives us back to
+        // `Configured`.
+        if suspended {
+            // Only drive resume when the host actually armed remote wakeup with
+            // `SET_FEATURE(DEVICE_REMOTE_WAKEUP)`; asserting it otherwise is a
+            // protocol violation the host is free to ignore.
+            if pressed && cx.shared.usb_dev.lock(|d| d.remote_wakeup_enabled()) {
+                remote_wakeup();
+            }
+            return;
         }
 
-        let tick = cx.shared.layout.tick();
-        match tick {
-            CustomEvent::Release(()) => unsafe { cortex_m::asm::bootload(0x1FFF0000 as _) },
+        match cx.shared.layout.tick() {
+            CustomEvent::Press(&CustomAction::LedToggle) => {
+                cx.shared
+                    .usb_class
+                    .lock(|k| k.device_mut().leds_mut().next_mode());
+                // Forward the toggle to the remote half so both strips cycle
+                // together instead of drifting out of sync.
+                if cx.local.tx_producer.enqueue(LED_CYCLE_CMD).is_ok() {
+                    rtic::pend(hal::pac::Interrupt::USART1);
+                }
+            }
+            CustomEvent::Release(&CustomAction::Bootloader) => unsafe {
+                cortex_m::asm::bootload(0x1FFF0000 as _)
+            },
             _ => (),
         }
 
-        // if this is the USB-side, send a USB keyboard report
+        // send a USB keyboard report
         let report: KbHidReport = cx.shared.layout.keycodes().collect();
         if cx
             .shared
@@ -228,6 +471,73 @@ mod app {
         }
     }
 
+    /// Service the TRRS `USART1` link in both directions. On TXE, drain the
+    /// outbound queue filled by [`tick`]; on RXNE, decode one framed byte and
+    /// feed it into the combined layout. A read error (framing/overrun) is
+    /// cleared by reading `DR` and the next start bit resynchronizes us; a byte
+    /// with an out-of-range index is dropped by [`deserialize_event`].
+    #[task(binds=USART1, priority=1, local=[serial_rx, serial_tx, tx_consumer], shared=[usb_dev, usb_class, layout, is_host])]
+    fn rx(mut cx: rx::Context) {
+        let is_host = *cx.shared.is_host;
+
+        // Drain queued outbound bytes as the transmitter empties. When the
+        // TDR fills, arm TXE so we are re-entered; once the queue is empty,
+        // disarm it so TXE stops firing.
+        let serial_tx = cx.local.serial_tx;
+        let tx_consumer = cx.local.tx_consumer;
+        while let Some(&byte) = tx_consumer.peek() {
+            match serial_tx.write(byte) {
+                Ok(()) => {
+                    tx_consumer.dequeue();
+                }
+                // The only reachable error is `WouldBlock` (TDR still full).
+                Err(_) => {
+                    serial_tx.listen();
+                    break;
+                }
+            }
+        }
+        if tx_consumer.peek().is_none() {
+            serial_tx.unlisten();
+        }
+
+        match cx.local.serial_rx.read() {
+            Ok(LED_CYCLE_CMD) => {
+                // The host toggled the underglow mode; cycle this strip too so
+                // both halves stay in sync.
+                cx.shared
+                    .usb_class
+                    .lock(|k| k.device_mut().leds_mut().next_mode());
+            }
+            Ok(byte) => {
+                if let Some(event) = deserialize_event(byte) {
+                    // A press from the remote half must wake a suspended host
+                    // just like a local one, otherwise half the keys could
+                    // never resume the bus.
+                    if event.is_press() {
+                        let suspended = cx
+                            .shared
+                            .usb_dev
+                            .lock(|d| d.state() == UsbDeviceState::Suspended && d.remote_wakeup_enabled());
+                        if suspended {
+                            remote_wakeup();
+                        }
+                    }
+                    // The received half lands opposite the host's own rows:
+                    // rows 5..=9 on the host, 0..=4 otherwise.
+                    let base = if is_host { 5 } else { 0 };
+                    cx.shared
+                        .layout
+                        .event(event.transform(|i, j| (i + base, j)));
+                }
+            }
+            // Overrun/framing error: the byte is discarded and we resync on the
+            // next start bit.
+            Err(nb::Error::Other(_)) => {}
+            Err(nb::Error::WouldBlock) => {}
+        }
+    }
+
     // USB events
     #[task(binds = USB_HP_CAN_TX, priority = 3, shared = [usb_dev, usb_class])]
     fn usb_tx(cx: usb_tx::Context) {